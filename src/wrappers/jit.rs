@@ -2,11 +2,20 @@
 use super::utils::{path_to_cstring, ptr_to_string};
 use crate::Tensor;
 use failure::Fallible;
-use libc::c_int;
+use libc::{c_char, c_int};
 use std::borrow::Borrow;
+use std::cell::RefCell;
+use std::io::{Read, Seek, SeekFrom};
+use std::os::raw::c_void;
 use torch_sys::*;
 
 /// Argument and output values for JIT models.
+///
+/// New variants may be added in the future to track the full set of types
+/// that `torch::jit::IValue` supports (e.g. futures, ranges, graphs), so
+/// this enum is marked `#[non_exhaustive]`: downstream matches must include
+/// a wildcard arm.
+#[non_exhaustive]
 #[derive(Debug, PartialEq)]
 pub enum IValue {
     None,
@@ -15,7 +24,19 @@ pub enum IValue {
     Int(i64),
     Bool(bool),
     Tuple(Vec<IValue>),
+    IntList(Vec<i64>),
+    DoubleList(Vec<f64>),
+    BoolList(Vec<bool>),
     String(String),
+    StringList(Vec<String>),
+    TensorList(Vec<Tensor>),
+    /// A generic, possibly heterogeneous, list of ivalues.
+    GenericList(Vec<IValue>),
+    /// A generic dictionary. This is represented as an ordered list of
+    /// key/value pairs rather than a `HashMap` because keys may be
+    /// `Double`s, and `f64` does not implement `Eq`/`Hash`.
+    GenericDict(Vec<(IValue, IValue)>),
+    Object(Object),
 }
 
 impl IValue {
@@ -40,6 +61,47 @@ impl IValue {
                     let c_str = std::ffi::CString::new(string.as_str())?;
                     ati_string(c_str.as_ptr())
                 }
+                IValue::IntList(v) => ati_int_list(v.as_ptr(), v.len() as c_int),
+                IValue::DoubleList(v) => ati_double_list(v.as_ptr(), v.len() as c_int),
+                IValue::BoolList(v) => {
+                    let v: Vec<c_char> = v.iter().map(|&b| if b { 1 } else { 0 }).collect();
+                    ati_bool_list(v.as_ptr(), v.len() as c_int)
+                }
+                IValue::StringList(v) => {
+                    let c_strings = v
+                        .iter()
+                        .map(|s| std::ffi::CString::new(s.as_str()))
+                        .collect::<Result<Vec<_>, _>>()?;
+                    let ptrs: Vec<_> = c_strings.iter().map(|s| s.as_ptr()).collect();
+                    ati_string_list(ptrs.as_ptr(), ptrs.len() as c_int)
+                }
+                IValue::TensorList(v) => {
+                    let v: Vec<_> = v.iter().map(|t| t.c_tensor).collect();
+                    ati_tensor_list(v.as_ptr(), v.len() as c_int)
+                }
+                IValue::GenericList(v) => {
+                    let v = v.iter().map(Self::to_c).collect::<Fallible<Vec<_>>>()?;
+                    let list = ati_generic_list(v.as_ptr(), v.len() as c_int);
+                    for x in v {
+                        ati_free(x);
+                    }
+
+                    list
+                }
+                IValue::GenericDict(v) => {
+                    let v = v
+                        .iter()
+                        .map(|(key, value)| Ok((key.to_c()?, value.to_c()?)))
+                        .collect::<Fallible<Vec<_>>>()?;
+                    let flat: Vec<_> = v.iter().flat_map(|&(k, v)| vec![k, v]).collect();
+                    let dict = ati_generic_dict(flat.as_ptr(), v.len() as c_int);
+                    for x in flat {
+                        ati_free(x);
+                    }
+
+                    dict
+                }
+                IValue::Object(object) => ati_clone(object.c_ivalue),
             }
         });
         Ok(c)
@@ -72,16 +134,141 @@ impl IValue {
                     .collect();
                 IValue::Tuple(vec?)
             }
+            6 => {
+                let len = unsafe_torch_err!({ ati_length(c_ivalue) });
+                let mut v: Vec<i64> = vec![0; len as usize];
+                unsafe_torch_err!(ati_to_int_list(c_ivalue, v.as_mut_ptr(), len));
+                IValue::IntList(v)
+            }
+            7 => {
+                let len = unsafe_torch_err!({ ati_length(c_ivalue) });
+                let mut v: Vec<f64> = vec![0.; len as usize];
+                unsafe_torch_err!(ati_to_double_list(c_ivalue, v.as_mut_ptr(), len));
+                IValue::DoubleList(v)
+            }
+            8 => {
+                let len = unsafe_torch_err!({ ati_length(c_ivalue) });
+                let mut v: Vec<c_char> = vec![0; len as usize];
+                unsafe_torch_err!(ati_to_bool_list(c_ivalue, v.as_mut_ptr(), len));
+                IValue::BoolList(v.into_iter().map(|b| b != 0).collect())
+            }
             9 => {
                 let ptr = unsafe_torch_err!({ ati_to_string(c_ivalue) });
                 let string = unsafe { ptr_to_string(ptr) }.unwrap(); // TODO: better error handling
                 IValue::String(string)
             }
+            10 => {
+                let len = unsafe_torch_err!({ ati_length(c_ivalue) });
+                let mut ptrs: Vec<_> = (0..len).map(|_| std::ptr::null_mut::<c_char>()).collect();
+                unsafe_torch_err!(ati_to_string_list(c_ivalue, ptrs.as_mut_ptr(), len));
+                let strings: Result<Vec<_>, _> = ptrs
+                    .into_iter()
+                    .map(|ptr| unsafe { ptr_to_string(ptr) })
+                    .collect();
+                IValue::StringList(strings?)
+            }
+            11 => {
+                let len = unsafe_torch_err!({ ati_length(c_ivalue) });
+                let mut c_tensors: Vec<_> =
+                    (0..len).map(|_| std::ptr::null_mut::<C_tensor>()).collect();
+                unsafe_torch_err!(ati_to_tensor_list(c_ivalue, c_tensors.as_mut_ptr(), len));
+                let tensors = c_tensors
+                    .into_iter()
+                    .map(|c_tensor| crate::Tensor { c_tensor })
+                    .collect();
+                IValue::TensorList(tensors)
+            }
+            12 => {
+                let len = unsafe_torch_err!({ ati_length(c_ivalue) });
+                let mut c_ivalues: Vec<_> =
+                    (0..len).map(|_| std::ptr::null_mut::<CIValue>()).collect();
+                unsafe_torch_err!(ati_to_generic_list(c_ivalue, c_ivalues.as_mut_ptr(), len));
+                let vec: Result<Vec<_>, _> = c_ivalues
+                    .iter()
+                    .map(|&c_ivalue| (Self::of_c(c_ivalue)))
+                    .collect();
+                IValue::GenericList(vec?)
+            }
+            13 => {
+                let len = unsafe_torch_err!({ ati_length(c_ivalue) });
+                let mut c_ivalues: Vec<_> = (0..2 * len)
+                    .map(|_| std::ptr::null_mut::<CIValue>())
+                    .collect();
+                unsafe_torch_err!(ati_to_generic_dict(c_ivalue, c_ivalues.as_mut_ptr(), len));
+                let mut vec = Vec::with_capacity(len as usize);
+                for kv in c_ivalues.chunks(2) {
+                    vec.push((Self::of_c(kv[0])?, Self::of_c(kv[1])?));
+                }
+                IValue::GenericDict(vec)
+            }
+            14 => IValue::Object(Object { c_ivalue }),
             _ => Err(format_err!("unhandled tag {}", tag))?,
         };
-        unsafe_torch_err!({ ati_free(c_ivalue) });
+        if tag != 14 {
+            unsafe_torch_err!({ ati_free(c_ivalue) });
+        }
         Ok(v)
     }
+
+    /// Performs a depth-first traversal of this value, calling `f` on every
+    /// node encountered. Returning `true` from `f` prunes further descent
+    /// into that node's children.
+    ///
+    /// `Tuple` and `GenericList` are visited element-wise, and
+    /// `GenericDict` is visited over both its keys and its values.
+    pub fn visit<E, F: FnMut(&IValue) -> Result<bool, E>>(&self, f: &mut F) -> Result<(), E> {
+        if f(self)? {
+            return Ok(());
+        }
+        match self {
+            IValue::Tuple(v) | IValue::GenericList(v) => {
+                for x in v {
+                    x.visit(f)?;
+                }
+            }
+            IValue::GenericDict(v) => {
+                for (key, value) in v {
+                    key.visit(f)?;
+                    value.visit(f)?;
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Collects all the tensors contained in this value, including ones
+    /// nested arbitrarily deep inside tuples, lists, and dicts.
+    pub fn collect_tensors(&self) -> Vec<&Tensor> {
+        let mut tensors = vec![];
+        let _: Result<(), ()> = self.visit(&mut |v| {
+            match v {
+                IValue::Tensor(tensor) => tensors.push(tensor),
+                IValue::TensorList(ts) => tensors.extend(ts.iter()),
+                _ => {}
+            }
+            Ok(false)
+        });
+        tensors
+    }
+}
+
+/// An opaque reference to a TorchScript class instance returned by a model.
+///
+/// Attribute access is not yet exposed; this mostly serves as a
+/// pass-through handle so that objects embedded in a nested result can be
+/// round-tripped via [`IValue::to_c`]/[`IValue::of_c`] without loss.
+#[derive(Debug, PartialEq)]
+pub struct Object {
+    c_ivalue: *mut CIValue,
+}
+
+unsafe impl Send for Object {}
+
+impl Drop for Object {
+    fn drop(&mut self) {
+        unsafe_torch!({ ati_free(self.c_ivalue) })
+    }
 }
 
 /// A jit PyTorch module.
@@ -106,11 +293,66 @@ impl Drop for CModule {
 impl CModule {
     /// Loads a PyTorch saved JIT model from a file.
     pub fn load<T: AsRef<std::path::Path>>(path: T) -> Fallible<CModule> {
+        Self::load_on_device(path, crate::Device::Cpu)
+    }
+
+    /// Loads a PyTorch saved JIT model from a file onto the given device.
+    pub fn load_on_device<T: AsRef<std::path::Path>>(
+        path: T,
+        device: crate::Device,
+    ) -> Fallible<CModule> {
         let path = path_to_cstring(path)?;
-        let c_module = unsafe_torch_err!({ atm_load(path.as_ptr()) });
+        let c_module = unsafe_torch_err!({ atm_load_on_device(path.as_ptr(), device.c_int()) });
         Ok(CModule { c_module })
     }
 
+    /// Moves the module's parameters and buffers to the given device.
+    pub fn to(&mut self, device: crate::Device) -> Fallible<()> {
+        unsafe_torch_err!({ atm_to(self.c_module, device.c_int(), 0) });
+        Ok(())
+    }
+
+    /// Loads a PyTorch saved JIT model from a `Read + Seek` stream, e.g. a
+    /// model embedded in the binary or fetched over the network, without
+    /// having to write it to disk first.
+    pub fn load_data<R: Read + Seek>(reader: R) -> Fallible<CModule> {
+        Self::load_data_on_device(reader, crate::Device::Cpu)
+    }
+
+    /// Loads a PyTorch saved JIT model from a `Read + Seek` stream onto the
+    /// given device.
+    pub fn load_data_on_device<R: Read + Seek>(
+        mut reader: R,
+        device: crate::Device,
+    ) -> Fallible<CModule> {
+        let ctx = &mut reader as *mut R as *mut c_void;
+        let c_module = unsafe_torch_err!({
+            atm_load_str(ctx, read_callback::<R>, seek_callback::<R>, device.c_int())
+        });
+        Ok(CModule { c_module })
+    }
+
+    /// Loads a PyTorch saved JIT model from an in-memory buffer, e.g. one
+    /// embedded via `include_bytes!`.
+    pub fn load_from_slice(buf: &[u8]) -> Fallible<CModule> {
+        Self::load_data(std::io::Cursor::new(buf))
+    }
+
+    /// Saves the module to a file, e.g. so that it can be reloaded once it
+    /// has been moved to a device or fine-tuned via [`TrainableCModule`].
+    pub fn save<T: AsRef<std::path::Path>>(&self, path: T) -> Fallible<()> {
+        let path = path_to_cstring(path)?;
+        unsafe_torch_err!({ atm_save(self.c_module, path.as_ptr()) });
+        Ok(())
+    }
+
+    /// Saves the module to a `Write` stream.
+    pub fn save_to<W: std::io::Write>(&self, mut writer: W) -> Fallible<()> {
+        let ctx = &mut writer as *mut W as *mut c_void;
+        unsafe_torch_err!({ atm_save_str(self.c_module, ctx, write_callback::<W>) });
+        Ok(())
+    }
+
     /// Performs the forward pass for a model on some specified tensor inputs.
     pub fn forward_ts<T: Borrow<Tensor>>(&self, ts: &[T]) -> Fallible<Tensor> {
         let ts: Vec<_> = ts.iter().map(|x| x.borrow().c_tensor).collect();
@@ -132,6 +374,157 @@ impl CModule {
         }
         IValue::of_c(c_ivalue)
     }
+
+    /// Runs a method other than `forward` on the module, e.g. `encode` or
+    /// `generate`, passing it some specified tensor inputs.
+    pub fn method_ts<T: Borrow<Tensor>>(&self, method_name: &str, ts: &[T]) -> Fallible<Tensor> {
+        let ts: Vec<_> = ts.iter().map(|x| x.borrow().c_tensor).collect();
+        let method_name = std::ffi::CString::new(method_name)?;
+        let c_tensor = unsafe_torch_err!({
+            atm_method(
+                self.c_module,
+                method_name.as_ptr(),
+                ts.as_ptr(),
+                ts.len() as c_int,
+            )
+        });
+        Ok(Tensor { c_tensor })
+    }
+
+    /// Runs a method other than `forward` on the module, e.g. `encode` or
+    /// `generate`, passing it some specified ivalue inputs.
+    pub fn method_is<T: Borrow<IValue>>(&self, method_name: &str, ts: &[T]) -> Fallible<IValue> {
+        let ts = ts
+            .iter()
+            .map(|x| x.borrow().to_c())
+            .collect::<Fallible<Vec<_>>>()?;
+        let method_name = std::ffi::CString::new(method_name)?;
+        let c_ivalue = unsafe_torch_err!({
+            atm_method_(
+                self.c_module,
+                method_name.as_ptr(),
+                ts.as_ptr(),
+                ts.len() as c_int,
+            )
+        });
+        for x in ts {
+            unsafe { ati_free(x) }
+        }
+        IValue::of_c(c_ivalue)
+    }
+
+    /// Returns the names and values of all the tensors stored as parameters
+    /// of the module, e.g. `encoder.layer.0.weight`.
+    pub fn named_parameters(&self) -> Fallible<Vec<(String, Tensor)>> {
+        let v: RefCell<Vec<(String, Tensor)>> = RefCell::new(vec![]);
+        unsafe_torch_err!({
+            atm_named_parameters(
+                self.c_module,
+                &v as *const _ as *mut libc::c_void,
+                add_callback,
+            )
+        });
+        Ok(v.into_inner())
+    }
+}
+
+extern "C" fn read_callback<R: Read>(ctx: *mut c_void, buf: *mut u8, len: usize) -> isize {
+    let reader = unsafe { &mut *(ctx as *mut R) };
+    let slice = unsafe { std::slice::from_raw_parts_mut(buf, len) };
+    match reader.read(slice) {
+        Ok(n) => n as isize,
+        Err(_) => -1,
+    }
+}
+
+extern "C" fn write_callback<W: std::io::Write>(
+    ctx: *mut c_void,
+    buf: *const u8,
+    len: usize,
+) -> isize {
+    let writer = unsafe { &mut *(ctx as *mut W) };
+    let slice = unsafe { std::slice::from_raw_parts(buf, len) };
+    match writer.write(slice) {
+        Ok(n) => n as isize,
+        Err(_) => -1,
+    }
+}
+
+extern "C" fn seek_callback<R: Seek>(ctx: *mut c_void, offset: i64, whence: c_int) -> i64 {
+    let reader = unsafe { &mut *(ctx as *mut R) };
+    let pos = match whence {
+        0 => SeekFrom::Start(offset as u64),
+        1 => SeekFrom::Current(offset),
+        2 => SeekFrom::End(offset),
+        _ => return -1,
+    };
+    reader.seek(pos).map(|p| p as i64).unwrap_or(-1)
+}
+
+extern "C" fn add_callback(data: *mut libc::c_void, name: *const c_char, c_tensor: *mut C_tensor) {
+    let name = match unsafe { ptr_to_string(name as *mut c_char) } {
+        Err(_) => return,
+        Ok(name) => name,
+    };
+    let v = unsafe { &*(data as *const RefCell<Vec<(String, Tensor)>>) };
+    v.borrow_mut().push((name, Tensor { c_tensor }));
+}
+
+/// A jit PyTorch module tied to a [`crate::nn::VarStore`], so that the
+/// tensors it was exported with can be fine-tuned from Rust rather than
+/// only used for inference.
+#[derive(Debug)]
+pub struct TrainableCModule {
+    inner: CModule,
+}
+
+impl TrainableCModule {
+    /// Loads a PyTorch saved JIT model from a file and registers every one
+    /// of its parameters on `path_` as a trainable variable, so the module
+    /// can be fine-tuned via the variable store's optimizer.
+    ///
+    /// Each tensor returned by `named_parameters` is the module's actual
+    /// parameter storage, not a copy of it, so it is registered as-is
+    /// (rather than via `Path::var_copy`, which would initialize a
+    /// disconnected variable): gradients computed by running `forward_ts`/
+    /// `forward_is` and calling `.backward()` land on these same tensors,
+    /// and stepping an optimizer over them updates the weights the module
+    /// itself uses on the next forward pass.
+    pub fn load<T: AsRef<std::path::Path>>(
+        path: T,
+        path_: crate::nn::Path,
+    ) -> Fallible<TrainableCModule> {
+        let inner = CModule::load(path)?;
+        for (name, tensor) in inner.named_parameters()? {
+            let _var = path_.add(&name, tensor, true);
+        }
+        Ok(TrainableCModule { inner })
+    }
+
+    /// Sets the module in training mode, affecting e.g. dropout or batch-norm.
+    pub fn set_train(&mut self) {
+        unsafe_torch!({ atm_train(self.inner.c_module) })
+    }
+
+    /// Sets the module in evaluation mode, affecting e.g. dropout or batch-norm.
+    pub fn set_eval(&mut self) {
+        unsafe_torch!({ atm_eval(self.inner.c_module) })
+    }
+
+    /// Performs the forward pass for a model on some specified tensor inputs.
+    pub fn forward_ts<T: Borrow<Tensor>>(&self, ts: &[T]) -> Fallible<Tensor> {
+        self.inner.forward_ts(ts)
+    }
+
+    /// Performs the forward pass for a model on some specified ivalue input.
+    pub fn forward_is<T: Borrow<IValue>>(&self, ts: &[T]) -> Fallible<IValue> {
+        self.inner.forward_is(ts)
+    }
+
+    /// Saves the module, including its fine-tuned weights, to a file.
+    pub fn save<T: AsRef<std::path::Path>>(&self, path: T) -> Fallible<()> {
+        self.inner.save(path)
+    }
 }
 
 #[cfg(test)]
@@ -143,4 +536,112 @@ mod tests {
         let ivalue2 = IValue::of_c(ivalue.to_c().unwrap()).unwrap();
         assert_eq!(format!("{:?}", ivalue), format!("{:?}", ivalue2));
     }
+
+    #[test]
+    fn ivalue_lists_and_dict() {
+        let ivalue = IValue::GenericDict(vec![
+            (
+                IValue::String("ints".to_string()),
+                IValue::IntList(vec![1, 2, 3]),
+            ),
+            (
+                IValue::String("doubles".to_string()),
+                IValue::DoubleList(vec![1.5, -2.5]),
+            ),
+            (
+                IValue::String("bools".to_string()),
+                IValue::BoolList(vec![true, false, true]),
+            ),
+            (
+                IValue::String("strings".to_string()),
+                IValue::StringList(vec!["a".to_string(), "b".to_string()]),
+            ),
+            (
+                IValue::String("nested".to_string()),
+                IValue::GenericList(vec![IValue::Int(1), IValue::None]),
+            ),
+        ]);
+        let ivalue2 = IValue::of_c(ivalue.to_c().unwrap()).unwrap();
+        assert_eq!(format!("{:?}", ivalue), format!("{:?}", ivalue2));
+    }
+
+    #[test]
+    fn ivalue_tensor_list_round_trip() {
+        let t1 = crate::Tensor::of_slice(&[1i64, 2, 3]);
+        let t2 = crate::Tensor::of_slice(&[4i64, 5, 6]);
+        let ivalue = IValue::TensorList(vec![t1, t2]);
+        let ivalue2 = IValue::of_c(ivalue.to_c().unwrap()).unwrap();
+        assert_eq!(format!("{:?}", ivalue), format!("{:?}", ivalue2));
+    }
+
+    #[test]
+    fn collect_tensors_finds_nested_and_list_tensors() {
+        let t1 = crate::Tensor::of_slice(&[1i64]);
+        let t2 = crate::Tensor::of_slice(&[2i64]);
+        let t3 = crate::Tensor::of_slice(&[3i64]);
+        let ivalue = IValue::GenericDict(vec![(
+            IValue::String("outputs".to_string()),
+            IValue::Tuple(vec![
+                IValue::Tensor(t1),
+                IValue::TensorList(vec![t2, t3]),
+                IValue::Int(0),
+            ]),
+        )]);
+        assert_eq!(ivalue.collect_tensors().len(), 3);
+    }
+
+    #[test]
+    fn visit_prunes_subtree_when_closure_returns_true() {
+        let ivalue = IValue::Tuple(vec![
+            IValue::GenericList(vec![IValue::Int(1), IValue::Int(2)]),
+            IValue::Tuple(vec![IValue::Int(1), IValue::Int(2)]),
+        ]);
+        let mut visited = 0;
+        ivalue
+            .visit(&mut |v: &IValue| -> Result<bool, ()> {
+                visited += 1;
+                Ok(matches!(v, IValue::GenericList(_)))
+            })
+            .unwrap();
+        // self + pruned GenericList (its two Ints skipped) + inner Tuple + its
+        // two Ints = 5 nodes visited.
+        assert_eq!(visited, 5);
+    }
+
+    #[test]
+    fn read_and_seek_callback_drive_the_underlying_reader() {
+        let mut data = std::io::Cursor::new(vec![1u8, 2, 3, 4]);
+        let ctx = &mut data as *mut std::io::Cursor<Vec<u8>> as *mut std::os::raw::c_void;
+
+        let pos = super::seek_callback::<std::io::Cursor<Vec<u8>>>(ctx, 2, 0);
+        assert_eq!(pos, 2);
+
+        let mut buf = [0u8; 2];
+        let n = super::read_callback::<std::io::Cursor<Vec<u8>>>(ctx, buf.as_mut_ptr(), buf.len());
+        assert_eq!(n, 2);
+        assert_eq!(buf, [3, 4]);
+    }
+
+    #[test]
+    fn write_callback_appends_to_the_underlying_writer() {
+        let mut data: Vec<u8> = vec![];
+        let ctx = &mut data as *mut Vec<u8> as *mut std::os::raw::c_void;
+        let buf = [5u8, 6, 7];
+        let n = super::write_callback::<Vec<u8>>(ctx, buf.as_ptr(), buf.len());
+        assert_eq!(n, 3);
+        assert_eq!(data, vec![5, 6, 7]);
+    }
+
+    #[test]
+    fn load_on_device_missing_file_is_an_error() {
+        let result = super::CModule::load_on_device("/nonexistent/model.pt", crate::Device::Cpu);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn trainable_cmodule_missing_file_is_an_error() {
+        let vs = crate::nn::VarStore::new(crate::Device::Cpu);
+        let result = super::TrainableCModule::load("/nonexistent/model.pt", vs.root());
+        assert!(result.is_err());
+    }
 }